@@ -1,5 +1,7 @@
 //! All error types used through the library.
 
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
 use thiserror::Error;
 
@@ -64,8 +66,17 @@ pub enum TaskError {
     /// If that service is temporarily unavailable the task should raise an `ExpectedError`.
     ///
     /// Tasks are always retried with capped exponential backoff.
-    #[error("task raised expected error: {0}")]
-    ExpectedError(String),
+    ///
+    /// Construct this with [`TaskError::expected`], optionally chaining
+    /// [`with_source`](TaskError::with_source) to attach the original error that caused it.
+    #[error("task raised expected error: {message}")]
+    ExpectedError {
+        /// The displayed error message.
+        message: String,
+        /// The original error that caused this one, if any.
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
 
     /// Should be used when a task encounters an error that is unexpected.
     ///
@@ -73,8 +84,17 @@ pub enum TaskError {
     /// when this error is encountered is determined by the
     /// [`TaskOptions::retry_for_unexpected`](../task/struct.TaskOptions.html#structfield.retry_for_unexpected)
     /// setting.
-    #[error("task raised unexpected error: {0}")]
-    UnexpectedError(String),
+    ///
+    /// Construct this with [`TaskError::unexpected`], optionally chaining
+    /// [`with_source`](TaskError::with_source) to attach the original error that caused it.
+    #[error("task raised unexpected error: {message}")]
+    UnexpectedError {
+        /// The displayed error message.
+        message: String,
+        /// The original error that caused this one, if any.
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
 
     /// Raised when a task runs over its time limit specified by the
     /// [`TaskOptions::time_limit`](../task/struct.TaskOptions.html#structfield.time_limit) setting.
@@ -95,6 +115,150 @@ pub enum TaskError {
     /// to manually trigger a retry from within a task.
     #[error("task retry triggered")]
     Retry(Option<DateTime<Utc>>),
+
+    /// A task can return this error variant when a downstream service has told it
+    /// exactly how long to wait before trying again, e.g. via a `Retry-After` header.
+    ///
+    /// The tracer will schedule the retry at `now + retry_after`, overriding the
+    /// computed exponential-backoff countdown, but `max_retries` is still respected.
+    #[error("task was rate limited: {message}")]
+    RateLimited {
+        /// How long to wait before retrying, as provided by the downstream service.
+        retry_after: Duration,
+        /// A human-readable description of the rate limit that was hit.
+        message: String,
+    },
+}
+
+impl TaskError {
+    /// Create a `TaskError::ExpectedError` with the given display message and no source.
+    ///
+    /// Use [`with_source`](TaskError::with_source) to attach the original error, if any.
+    pub fn expected(message: impl Into<String>) -> Self {
+        TaskError::ExpectedError {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Create a `TaskError::UnexpectedError` with the given display message and no source.
+    ///
+    /// Use [`with_source`](TaskError::with_source) to attach the original error, if any.
+    pub fn unexpected(message: impl Into<String>) -> Self {
+        TaskError::UnexpectedError {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Attach `err` as the underlying cause of an `ExpectedError` or `UnexpectedError`.
+    ///
+    /// Has no effect on any other variant.
+    pub fn with_source(self, err: impl std::error::Error + Send + Sync + 'static) -> Self {
+        match self {
+            TaskError::ExpectedError { message, .. } => TaskError::ExpectedError {
+                message,
+                source: Some(Box::new(err)),
+            },
+            TaskError::UnexpectedError { message, .. } => TaskError::UnexpectedError {
+                message,
+                source: Some(Box::new(err)),
+            },
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod task_error_tests {
+    use super::*;
+    use std::error::Error;
+    use std::fmt;
+
+    #[derive(Debug)]
+    struct OriginalFailure;
+
+    impl fmt::Display for OriginalFailure {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "the original failure")
+        }
+    }
+
+    impl std::error::Error for OriginalFailure {}
+
+    #[test]
+    fn with_expected_err_keeps_the_original_error_in_the_source_chain() {
+        let result: Result<(), OriginalFailure> = Err(OriginalFailure);
+        let err = result.with_expected_err("doing the thing").unwrap_err();
+
+        assert_eq!(err.to_string(), "task raised expected error: doing the thing");
+
+        let source = err
+            .source()
+            .expect("with_expected_err should attach a source");
+        assert_eq!(source.to_string(), "the original failure");
+        assert!(source.downcast_ref::<OriginalFailure>().is_some());
+    }
+}
+
+/// The outcome of consulting a [`RetryableStrategy`] about a task that has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Retry the task, following the default capped exponential backoff behavior.
+    Retry,
+
+    /// Retry the task, but not before the given duration has elapsed.
+    RetryAfter(Duration),
+
+    /// Do not retry the task, regardless of `max_retries`.
+    DoNotRetry,
+}
+
+/// Allows an app to classify a failed task's [`TaskError`] and decide whether (and when)
+/// it should be retried.
+///
+/// By default, retry behavior is derived entirely from the `TaskError` variant: an
+/// `ExpectedError` always retries, an `UnexpectedError` follows
+/// [`TaskOptions::retry_for_unexpected`](../task/struct.TaskOptions.html#structfield.retry_for_unexpected),
+/// and a `TimeoutError` retries like an expected error. Implementing this trait and
+/// registering it with the `Celery` app lets a task inspect the concrete error (for
+/// example a wrapped HTTP status code or an error message) and override that default on
+/// a per-error basis, without having to pick a different `TaskError` variant just to
+/// influence retry behavior.
+///
+/// The tracer consults the strategy, if one is configured, before falling back to the
+/// default variant-based logic.
+pub trait RetryableStrategy: Send + Sync {
+    /// Decide whether `err` should trigger a retry.
+    fn decide(&self, err: &TaskError) -> RetryDecision;
+}
+
+/// The default, variant-based retry decision for `err`, used when no
+/// [`RetryableStrategy`] is configured.
+///
+/// This only covers what can be determined from the `TaskError` variant itself; the
+/// `TaskOptions::retry_for_unexpected` setting still needs to be applied by the caller
+/// for `UnexpectedError`, since it isn't known to this module.
+fn default_retry_decision(err: &TaskError) -> RetryDecision {
+    match err {
+        TaskError::ExpectedError { .. } | TaskError::TimeoutError | TaskError::Retry(_) => {
+            RetryDecision::Retry
+        }
+        TaskError::UnexpectedError { .. } => RetryDecision::DoNotRetry,
+        TaskError::RateLimited { retry_after, .. } => RetryDecision::RetryAfter(*retry_after),
+    }
+}
+
+/// Decide whether `err` should trigger a retry, consulting `strategy` (if one is
+/// configured on the app) before falling back to [`default_retry_decision`].
+pub(crate) fn retry_decision_for(
+    err: &TaskError,
+    strategy: Option<&(dyn RetryableStrategy)>,
+) -> RetryDecision {
+    match strategy {
+        Some(strategy) => strategy.decide(err),
+        None => default_retry_decision(err),
+    }
 }
 
 /// Errors that can occur while tracing a task.
@@ -111,6 +275,317 @@ pub(crate) enum TraceError {
     /// Raised when a task should be retried.
     #[error("retrying task")]
     Retry(Option<DateTime<Utc>>),
+
+    /// Raised when a task would be retried, but the app's retry token budget has been
+    /// exhausted. The retry is converted into a terminal failure rather than being
+    /// rescheduled, so that callers can tell this apart from an ordinary failed task.
+    #[error("retry budget exhausted")]
+    RetryBudgetExhausted,
+}
+
+impl TraceError {
+    /// Resolve the [`TraceError`] the tracer should raise for a failed task.
+    ///
+    /// Consults `strategy` (if any) for a [`RetryDecision`], then gates a would-be retry
+    /// against `bucket`'s retry budget. A [`RetryDecision::RetryAfter`] — notably the one
+    /// produced for [`TaskError::RateLimited`] — is converted into an absolute
+    /// `TraceError::Retry` ETA of `now + retry_after`, overriding the computed
+    /// exponential-backoff countdown used for a plain [`RetryDecision::Retry`]. A manually
+    /// triggered [`TaskError::Retry`] is not passed to `strategy`; it carries its own eta
+    /// (set via `retry_with_eta`), which is preserved as-is rather than being discarded in
+    /// favor of the default countdown.
+    ///
+    /// `TaskOptions::retry_for_unexpected` is not applied here: with no `strategy`, an
+    /// `UnexpectedError` always resolves to `TraceError::TaskError`.
+    pub(crate) fn for_failed_task(
+        err: TaskError,
+        strategy: Option<&dyn RetryableStrategy>,
+        bucket: &RetryTokenBucket,
+    ) -> Self {
+        if let TaskError::Retry(eta) = err {
+            return if bucket.try_retry(&err) {
+                TraceError::Retry(eta)
+            } else {
+                TraceError::RetryBudgetExhausted
+            };
+        }
+
+        match retry_decision_for(&err, strategy) {
+            RetryDecision::DoNotRetry => TraceError::TaskError(err),
+            RetryDecision::Retry => {
+                if bucket.try_retry(&err) {
+                    TraceError::Retry(None)
+                } else {
+                    TraceError::RetryBudgetExhausted
+                }
+            }
+            RetryDecision::RetryAfter(retry_after) => {
+                if bucket.try_retry(&err) {
+                    let delay = chrono::Duration::from_std(retry_after)
+                        .unwrap_or_else(|_| chrono::Duration::zero());
+                    TraceError::Retry(Some(Utc::now() + delay))
+                } else {
+                    TraceError::RetryBudgetExhausted
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod retry_decision_tests {
+    use super::*;
+
+    struct AlwaysDoNotRetry;
+
+    impl RetryableStrategy for AlwaysDoNotRetry {
+        fn decide(&self, _err: &TaskError) -> RetryDecision {
+            RetryDecision::DoNotRetry
+        }
+    }
+
+    #[test]
+    fn strategy_overrides_the_default_decision() {
+        // An ExpectedError would retry by default; the strategy should override that.
+        let err = TaskError::expected("transient failure");
+        assert_eq!(default_retry_decision(&err), RetryDecision::Retry);
+        assert_eq!(
+            retry_decision_for(&err, Some(&AlwaysDoNotRetry)),
+            RetryDecision::DoNotRetry
+        );
+    }
+
+    #[test]
+    fn for_failed_task_converts_retry_to_retry_budget_exhausted_once_the_bucket_is_empty() {
+        let bucket = RetryTokenBucket::new(0);
+        let err = TaskError::expected("transient failure");
+
+        assert!(matches!(
+            TraceError::for_failed_task(err, None, &bucket),
+            TraceError::RetryBudgetExhausted
+        ));
+    }
+
+    #[test]
+    fn for_failed_task_converts_retry_after_to_retry_budget_exhausted_once_the_bucket_is_empty() {
+        let bucket = RetryTokenBucket::new(0);
+        let err = TaskError::RateLimited {
+            retry_after: Duration::from_secs(30),
+            message: "rate limited".to_string(),
+        };
+
+        assert!(matches!(
+            TraceError::for_failed_task(err, None, &bucket),
+            TraceError::RetryBudgetExhausted
+        ));
+    }
+
+    #[test]
+    fn for_failed_task_preserves_the_eta_on_a_manually_triggered_retry() {
+        let bucket = RetryTokenBucket::default();
+        let eta = Utc::now() + chrono::Duration::minutes(5);
+        let err = TaskError::Retry(Some(eta));
+
+        match TraceError::for_failed_task(err, None, &bucket) {
+            TraceError::Retry(Some(got)) => assert_eq!(got, eta),
+            other => panic!("expected TraceError::Retry(Some(eta)), got {:?}", other),
+        }
+    }
+}
+
+/// Default capacity of a [`RetryTokenBucket`] when an app doesn't configure its own.
+pub const DEFAULT_RETRY_TOKEN_BUCKET_CAPACITY: usize = 500;
+
+/// Tokens withdrawn from the retry token bucket for a retry triggered by a
+/// rate-limit or timeout style error.
+pub const RETRY_COST_THROTTLED: usize = 10;
+
+/// Tokens withdrawn from the retry token bucket for a retry triggered by a generic
+/// expected error.
+pub const RETRY_COST_EXPECTED: usize = 5;
+
+/// Tokens deposited back into the retry token bucket whenever a task completes
+/// successfully.
+pub const RETRY_REFILL: usize = 1;
+
+/// A token bucket shared across an entire `Celery` app that bounds the aggregate rate
+/// of task retries.
+///
+/// When a downstream dependency goes down, every in-flight task hitting an
+/// [`ExpectedError`](TaskError::ExpectedError) would otherwise retry with exponential
+/// backoff at the same time, hammering the dependency as it tries to recover. Before
+/// scheduling a retry, the tracer withdraws tokens from this bucket with
+/// [`withdraw`](RetryTokenBucket::withdraw); if the bucket holds fewer tokens than the
+/// retry costs, the retry is turned
+/// into a [`TraceError::RetryBudgetExhausted`] instead. Every task that completes
+/// successfully [`deposit`](RetryTokenBucket::deposit)s a small refill, capped at the
+/// bucket's capacity.
+#[derive(Debug)]
+pub struct RetryTokenBucket {
+    capacity: usize,
+    tokens: std::sync::atomic::AtomicUsize,
+}
+
+impl RetryTokenBucket {
+    /// Create a new bucket with the given `capacity`, starting out full.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tokens: std::sync::atomic::AtomicUsize::new(capacity),
+        }
+    }
+
+    /// Attempt to withdraw `cost` tokens for a retry attempt.
+    ///
+    /// Returns `true` if the tokens were available and the retry may proceed, or
+    /// `false` if the bucket doesn't have enough tokens left, in which case the retry
+    /// should be abandoned.
+    pub fn withdraw(&self, cost: usize) -> bool {
+        use std::sync::atomic::Ordering;
+
+        let mut current = self.tokens.load(Ordering::Relaxed);
+        loop {
+            if current < cost {
+                return false;
+            }
+            match self.tokens.compare_exchange_weak(
+                current,
+                current - cost,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(observed) => current = observed,
+            }
+        }
+    }
+
+    /// Deposit `amount` tokens back into the bucket, capped at its capacity.
+    pub fn deposit(&self, amount: usize) {
+        use std::sync::atomic::Ordering;
+
+        let mut current = self.tokens.load(Ordering::Relaxed);
+        loop {
+            let next = std::cmp::min(self.capacity, current + amount);
+            match self.tokens.compare_exchange_weak(
+                current,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(observed) => current = observed,
+            }
+        }
+    }
+
+    /// The number of tokens a retry of `err` would cost from this bucket.
+    fn cost_for(err: &TaskError) -> usize {
+        match err {
+            TaskError::TimeoutError | TaskError::RateLimited { .. } => RETRY_COST_THROTTLED,
+            _ => RETRY_COST_EXPECTED,
+        }
+    }
+
+    /// Withdraw the tokens a retry of `err` would cost.
+    ///
+    /// Returns `true` if the retry may proceed, or `false` if the budget is exhausted and
+    /// the retry should be converted into a [`TraceError::RetryBudgetExhausted`].
+    pub(crate) fn try_retry(&self, err: &TaskError) -> bool {
+        self.withdraw(Self::cost_for(err))
+    }
+
+    /// Deposit the standard [`RETRY_REFILL`] back into the bucket; called whenever a
+    /// task completes successfully.
+    pub(crate) fn note_task_success(&self) {
+        self.deposit(RETRY_REFILL);
+    }
+}
+
+impl Default for RetryTokenBucket {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETRY_TOKEN_BUCKET_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod retry_token_bucket_tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn withdraw_succeeds_while_tokens_remain_and_fails_once_exhausted() {
+        let bucket = RetryTokenBucket::new(10);
+
+        assert!(bucket.withdraw(4));
+        assert!(bucket.withdraw(4));
+        // Only 2 tokens left; a withdrawal of 4 should fail and leave the bucket untouched.
+        assert!(!bucket.withdraw(4));
+        assert!(bucket.withdraw(2));
+        assert!(!bucket.withdraw(1));
+    }
+
+    #[test]
+    fn deposit_is_capped_at_capacity() {
+        let bucket = RetryTokenBucket::new(5);
+
+        assert!(bucket.withdraw(5));
+        bucket.deposit(100);
+        // Capped at capacity, so only 5 tokens should be withdrawable.
+        assert!(bucket.withdraw(5));
+        assert!(!bucket.withdraw(1));
+    }
+
+    #[test]
+    fn concurrent_withdrawals_never_oversubscribe_the_bucket() {
+        let bucket = Arc::new(RetryTokenBucket::new(100));
+        let successes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let bucket = Arc::clone(&bucket);
+                let successes = Arc::clone(&successes);
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        if bucket.withdraw(1) {
+                            successes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Exactly 100 of the 200 attempted withdrawals should have succeeded, and the
+        // bucket should never have gone negative (which would panic in `withdraw` via
+        // overflow, or show up here as more than 100 successes).
+        assert_eq!(successes.load(std::sync::atomic::Ordering::Relaxed), 100);
+        assert!(!bucket.withdraw(1));
+    }
+
+    #[test]
+    fn concurrent_deposits_stay_capped_at_capacity() {
+        let bucket = Arc::new(RetryTokenBucket::new(10));
+        assert!(bucket.withdraw(10));
+
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let bucket = Arc::clone(&bucket);
+                thread::spawn(move || bucket.deposit(3))
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(bucket.withdraw(10));
+        assert!(!bucket.withdraw(1));
+    }
 }
 
 /// Errors that can occur at the broker level.
@@ -242,11 +717,56 @@ pub enum ContentTypeError {
     Unknown,
 }
 
+impl ContentTypeError {
+    /// Returns `true` if the message body itself is malformed and will never
+    /// successfully deserialize, no matter how many times delivery is retried, as
+    /// opposed to a transient issue.
+    ///
+    /// This lets a broker's delivery handling reject or ack-and-drop a poison message
+    /// instead of endlessly redelivering it.
+    pub fn is_malformed(&self) -> bool {
+        match self {
+            ContentTypeError::Json(err) => !err.is_io(),
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::Yaml(_) => true,
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::Pickle(_) => true,
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::MsgPackDecode(_) => true,
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::MsgPackEncode(_) => false,
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::MsgPackValue(_) => true,
+            // An unrecognized content type can never be decoded, no matter how many times
+            // delivery is retried.
+            ContentTypeError::Unknown => true,
+        }
+    }
+
+    /// The name of the content type this error originated from, e.g. `"json"`.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ContentTypeError::Json(_) => "json",
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::Yaml(_) => "yaml",
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::Pickle(_) => "pickle",
+            #[cfg(any(test, feature = "extra_content_types"))]
+            ContentTypeError::MsgPackDecode(_)
+            | ContentTypeError::MsgPackEncode(_)
+            | ContentTypeError::MsgPackValue(_) => "msgpack",
+            ContentTypeError::Unknown => "unknown",
+        }
+    }
+}
+
 /// Extension methods for `Result` types within a task body.
 ///
 /// These methods can be used to convert a `Result<T, E>` to a `Result<T, TaskError>` with the
 /// appropriate `TaskError` variant. The trait has a blanket implementation for any error type that implements
-/// [`std::error::Error`](https://doc.rust-lang.org/std/error/trait.Error.html).
+/// [`std::error::Error`](https://doc.rust-lang.org/std/error/trait.Error.html). The original error is
+/// kept as the `#[source]` of the resulting `TaskError`, so it still shows up in the `source()` chain
+/// even though the given `context` becomes the displayed message.
 pub trait TaskResultExt<T, E> {
     /// Convert the error type to a `TaskError::ExpectedError`.
     fn with_expected_err(self, context: &str) -> Result<T, TaskError>;
@@ -257,13 +777,13 @@ pub trait TaskResultExt<T, E> {
 
 impl<T, E> TaskResultExt<T, E> for Result<T, E>
 where
-    E: std::error::Error,
+    E: std::error::Error + Send + Sync + 'static,
 {
     fn with_expected_err(self, context: &str) -> Result<T, TaskError> {
-        self.map_err(|_failure| TaskError::ExpectedError(context.into()))
+        self.map_err(|failure| TaskError::expected(context).with_source(failure))
     }
 
     fn with_unexpected_err(self, context: &str) -> Result<T, TaskError> {
-        self.map_err(|_failure| TaskError::UnexpectedError(context.into()))
+        self.map_err(|failure| TaskError::unexpected(context).with_source(failure))
     }
 }